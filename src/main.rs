@@ -1,10 +1,72 @@
 use clap::{arg, App};
-use std::{fs, process, path::Path, io::Read, thread, sync::{mpsc::{self, Receiver, Sender}, Arc}};
-use regex::{Regex};
-use reqwest::{self, header::{HeaderName, HeaderValue}};
+use std::{fs, process, path::{Path, PathBuf}, io::{self, Write}, thread, time::Duration,
+          collections::{HashMap, hash_map::DefaultHasher}, hash::{Hash, Hasher},
+          sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex, Condvar}};
+use reqwest::{self, blocking::RequestBuilder, header::{HeaderName, HeaderValue}};
 use url::{Url};
 use pbr::ProgressBar;
 use serde_json;
+use openssl::symm::{decrypt, Cipher};
+use dirs;
+
+#[derive(Debug, Clone)]
+struct Segment {
+    uri: String,
+    filename: String,
+    key_uri: Option<String>,
+    iv: Option<[u8; 16]>,
+    sequence: u64
+}
+
+// One entry of a master playlist's `#EXT-X-STREAM-INF` variant list.
+#[derive(Debug, Clone)]
+struct Variant {
+    uri: String,
+    bandwidth: u64,
+    resolution: Option<String>
+}
+
+// Caps how many requests may be in flight to a given host at once, so that a
+// high total thread count doesn't translate into hammering a single origin
+// and tripping anti-DDoS throttling. Shared across all download threads.
+// This only reduces the failure rate in practice because `stream_attempt`
+// rejects non-2xx responses: a host that throttles with 429/503 needs those
+// treated as failures and retried, not saved as if they were segments.
+struct HostLimiter {
+    max_per_host: usize,
+    inflight: Mutex<HashMap<String, usize>>,
+    cv: Condvar
+}
+
+impl HostLimiter {
+    fn new(max_per_host: usize) -> HostLimiter {
+        HostLimiter {
+            max_per_host,
+            inflight: Mutex::new(HashMap::new()),
+            cv: Condvar::new()
+        }
+    }
+
+    fn acquire(&self, host: &str) {
+        let mut guard = self.inflight.lock().unwrap();
+        loop {
+            let count = *guard.get(host).unwrap_or(&0);
+            if count < self.max_per_host {
+                guard.insert(host.to_string(), count + 1);
+                return;
+            }
+            guard = self.cv.wait(guard).unwrap();
+        }
+    }
+
+    fn release(&self, host: &str) {
+        let mut guard = self.inflight.lock().unwrap();
+        if let Some(count) = guard.get_mut(host) {
+            *count -= 1;
+        }
+        self.cv.notify_all();
+    }
+}
 
 #[derive(Debug)]
 struct M3U8 {
@@ -12,17 +74,27 @@ struct M3U8 {
     base_url: String,
     output: String,
     header: String,
-    resume: bool
+    resume: bool,
+    quality: String,
+    max_per_host: usize,
+    cache_ttl: Duration,
+    refresh: bool
 }
 
 impl M3U8 {
-    fn new(path: &str, base_url: &str, output: &str, header: &str, resume: bool) -> M3U8 {
+    #[allow(clippy::too_many_arguments)]
+    fn new(path: &str, base_url: &str, output: &str, header: &str, resume: bool, quality: &str, max_per_host: usize,
+           cache_ttl: Duration, refresh: bool) -> M3U8 {
         M3U8 {
             path: String::from(path),
             base_url: String::from(base_url),
             output: String::from(output),
             header: String::from(header),
-            resume: resume
+            resume: resume,
+            quality: String::from(quality),
+            max_per_host: max_per_host,
+            cache_ttl: cache_ttl,
+            refresh: refresh
         }
     }
 
@@ -48,52 +120,380 @@ impl M3U8 {
         }
     }
 
-    fn load_m3u8(path: &str) -> Vec<String> {
-        let content = fs::read_to_string(path).unwrap_or_else(|op| {
+    // Splits an HLS attribute-list (e.g. `METHOD=AES-128,URI="key.bin"`) into
+    // key/value pairs, respecting commas embedded inside quoted values.
+    fn parse_attribute_list(attrs: &str) -> Vec<(String, String)> {
+        let mut parts = vec![];
+        let mut current = String::new();
+        let mut in_quotes = false;
+        for c in attrs.chars() {
+            match c {
+                '"' => { in_quotes = !in_quotes; current.push(c); },
+                ',' if !in_quotes => { parts.push(current.clone()); current.clear(); },
+                _ => current.push(c)
+            }
+        }
+        if !current.is_empty() {
+            parts.push(current);
+        }
+
+        let mut result = vec![];
+        for part in parts {
+            if let Some(eq) = part.find('=') {
+                let key = part[..eq].trim().to_string();
+                let mut value = part[eq + 1..].trim().to_string();
+                if value.starts_with('"') && value.ends_with('"') && value.len() >= 2 {
+                    value = value[1..value.len() - 1].to_string();
+                }
+                result.push((key, value));
+            }
+        }
+        result
+    }
+
+    // The IV to use when `#EXT-X-KEY` carries no explicit `IV` attribute: the
+    // segment's media sequence number, big-endian, in the low 8 bytes of a
+    // 16-byte block (see RFC 8216 section 5.2).
+    fn default_iv(sequence: u64) -> [u8; 16] {
+        let mut iv = [0u8; 16];
+        iv[8..].copy_from_slice(&sequence.to_be_bytes());
+        iv
+    }
+
+    fn read_playlist(path: &str) -> String {
+        fs::read_to_string(path).unwrap_or_else(|op| {
             println!("File {:?} read failed, error: {}", path, op);
             process::exit(0);
-        });
+        })
+    }
+
+    // Per-project cache directory for auto-fetched playlists.
+    fn cache_dir() -> PathBuf {
+        dirs::cache_dir().unwrap_or_else(|| PathBuf::from(".cache")).join("m3u8_downloader")
+    }
 
+    fn cache_path_for(url: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        M3U8::cache_dir().join(format!("{:x}.m3u8", hasher.finish()))
+    }
+
+    fn is_cache_stale(path: &Path, ttl: Duration) -> bool {
+        match fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(mtime) => mtime.elapsed().map_or(true, |age| age > ttl),
+            Err(_) => true
+        }
+    }
+
+    // Downloads the playlist body from `url`, reusing a cached copy unless it
+    // is missing, unreadable, older than `ttl` (by mtime), or `refresh` forces
+    // a re-fetch. Falls back to re-downloading (with the usual retry/backoff)
+    // whenever the cache can't be used.
+    fn fetch_playlist(url: &str, header: &[(String, String)], ttl: Duration, refresh: bool) -> String {
+        let cache_path = M3U8::cache_path_for(url);
+        if !refresh && !M3U8::is_cache_stale(&cache_path, ttl) {
+            if let Ok(content) = fs::read_to_string(&cache_path) {
+                return content;
+            }
+        }
+
+        fs::create_dir_all(M3U8::cache_dir()).unwrap();
+        let client = reqwest::blocking::Client::new();
+        let mut delay = Duration::from_secs(1);
+        for attempt in 1..=M3U8::MAX_TRIES {
+            let body = M3U8::apply_headers(client.get(url), header);
+            // `send()` succeeds for 4xx/5xx too; without `error_for_status` an
+            // error page would be cached as the playlist for the whole TTL.
+            match body.send().and_then(|resp| resp.error_for_status()).and_then(|resp| resp.text()) {
+                Ok(text) => {
+                    let _ = fs::write(&cache_path, &text);
+                    return text;
+                },
+                Err(e) => println!("playlist {} download failed (try {}/{}), error: {}", url, attempt, M3U8::MAX_TRIES, e)
+            }
+
+            if attempt < M3U8::MAX_TRIES {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+
+        println!("playlist {} gave up after {} tries", url, M3U8::MAX_TRIES);
+        process::exit(0);
+    }
+
+    fn is_master_playlist(content: &str) -> bool {
+        content.lines().any(|l| l.starts_with("#EXT-X-STREAM-INF:"))
+    }
+
+    // Master playlists list variants via `#EXT-X-STREAM-INF` followed by the
+    // variant's (possibly relative) URI on the next non-comment line.
+    fn parse_master_playlist(content: &str) -> Vec<Variant> {
+        let mut variants = vec![];
+        let mut pending: Option<(u64, Option<String>)> = None;
+        for line in content.lines() {
+            if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+                let mut bandwidth = 0u64;
+                let mut resolution = None;
+                for (k, v) in M3U8::parse_attribute_list(attrs) {
+                    match k.as_str() {
+                        "BANDWIDTH" => bandwidth = v.parse().unwrap_or(0),
+                        "RESOLUTION" => resolution = Some(v),
+                        _ => {}
+                    }
+                }
+                pending = Some((bandwidth, resolution));
+            } else if !line.starts_with('#') && !line.trim().is_empty() {
+                if let Some((bandwidth, resolution)) = pending.take() {
+                    variants.push(Variant { uri: line.trim().to_string(), bandwidth, resolution });
+                }
+            }
+        }
+        variants
+    }
+
+    // `quality` is "best" (highest bandwidth, the default), "worst" (lowest
+    // bandwidth), or a resolution hint like "720p" matched against the
+    // variant's vertical resolution, falling back to "best" if none match.
+    fn select_variant<'a>(variants: &'a [Variant], quality: &str) -> &'a Variant {
+        match quality {
+            "worst" => variants.iter().min_by_key(|v| v.bandwidth).unwrap(),
+            "best" => variants.iter().max_by_key(|v| v.bandwidth).unwrap(),
+            _ => {
+                let height = quality.trim_end_matches('p');
+                variants.iter()
+                    .find(|v| v.resolution.as_deref()
+                        .is_some_and(|r| r.ends_with(&format!("x{}", height))))
+                    .unwrap_or_else(|| variants.iter().max_by_key(|v| v.bandwidth).unwrap())
+            }
+        }
+    }
+
+    // Parses a media (non-master) playlist's segments, following any
+    // `#EXT-X-KEY`/`#EXT-X-MEDIA-SEQUENCE` bookkeeping that applies to them.
+    // Segments are recognised by extension (`.ts`, `.m4s`, `.aac`) rather than
+    // assumed, and may be absolute URLs or relative paths.
+    fn parse_segments(content: &str) -> Vec<Segment> {
         let mut list = vec![];
-        let ts_match = Regex::new(r"[a-zA-Z0-9]+\.ts").unwrap();
+        let mut sequence: u64 = 0;
+        let mut key_uri: Option<String> = None;
+        let mut iv: Option<[u8; 16]> = None;
         for line in content.lines() {
-            // Get encrypt key file.
-            if let Some(pos) = ts_match.find(line) {
-                list.push(String::from(pos.as_str()));
+            if let Some(rest) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                sequence = rest.trim().parse().unwrap_or(0);
+            } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+                key_uri = None;
+                iv = None;
+                for (k, v) in M3U8::parse_attribute_list(attrs) {
+                    match k.as_str() {
+                        "METHOD" if v == "NONE" => {},
+                        "URI" => key_uri = Some(v),
+                        "IV" => {
+                            let hex = v.trim_start_matches("0x").trim_start_matches("0X");
+                            if let Ok(bytes) = hex::decode(hex) {
+                                if bytes.len() == 16 {
+                                    let mut buf = [0u8; 16];
+                                    buf.copy_from_slice(&bytes);
+                                    iv = Some(buf);
+                                }
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+            } else {
+                let trimmed = line.trim();
+                // Tokenized CDN URLs carry a query/fragment after the real
+                // extension (e.g. `seg.ts?token=abc`); strip it before
+                // testing the extension so those segments aren't dropped.
+                let path_part = trimmed.split(['?', '#']).next().unwrap_or(trimmed);
+                let lower = path_part.to_lowercase();
+                let is_segment = !trimmed.is_empty() && !trimmed.starts_with('#')
+                    && (lower.ends_with(".ts") || lower.ends_with(".m4s") || lower.ends_with(".aac"));
+                if is_segment {
+                    let filename = path_part.rsplit('/').next().unwrap_or(path_part).to_string();
+                    list.push(Segment {
+                        uri: trimmed.to_string(),
+                        filename,
+                        key_uri: key_uri.clone(),
+                        iv,
+                        sequence
+                    });
+                    sequence += 1;
+                }
             }
         }
         list
     }
 
-    fn download_ts(base_url: &str, list: &[String], tx: &Sender<(String, Vec<u8>)>, header: &[(String, String)])
-    {
+    // Loads the configured playlist, transparently resolving a master
+    // playlist down to the selected variant's media playlist first.
+    fn load_m3u8(&self) -> Vec<Segment> {
+        let content = if self.path.is_empty() {
+            let header = self.parse_header();
+            M3U8::fetch_playlist(&self.base_url, &header, self.cache_ttl, self.refresh)
+        } else {
+            M3U8::read_playlist(&self.path)
+        };
+        if !M3U8::is_master_playlist(&content) {
+            return M3U8::parse_segments(&content);
+        }
+
+        let variants = M3U8::parse_master_playlist(&content);
+        if variants.is_empty() {
+            println!("m3u8 format is invalid");
+            process::exit(0);
+        }
+        let variant = M3U8::select_variant(&variants, &self.quality);
+        println!("selected variant: bandwidth={} resolution={:?}", variant.bandwidth, variant.resolution);
+
+        let url = Url::parse(&self.base_url).unwrap().join(&variant.uri).unwrap();
+        let header = self.parse_header();
         let client = reqwest::blocking::Client::new();
+        let body = M3U8::apply_headers(client.get(url.as_str()), &header);
+        let media_content = body.send().and_then(|r| r.text()).unwrap_or_else(|e| {
+            println!("variant playlist {} download failed, error: {}", url, e);
+            process::exit(0);
+        });
+        M3U8::parse_segments(&media_content)
+    }
+
+    fn apply_headers(mut body: RequestBuilder, header: &[(String, String)]) -> RequestBuilder {
+        for h in header {
+            body = body.header(HeaderName::from_bytes(h.0.as_bytes()).unwrap(),
+                         HeaderValue::from_bytes(h.1.as_bytes()).unwrap());
+        }
+        body
+    }
 
-        for ts in list {
-            let url = Url::parse(base_url).unwrap().join(&ts).unwrap();
-            let mut body = client.get(url.as_str());
-            for h in header {
-                body = body.header(HeaderName::from_bytes(&h.0.as_bytes()).unwrap(), 
-                             HeaderValue::from_bytes(&h.1.as_bytes()).unwrap());
+    // Downloads every key referenced by `list` at most once, keyed by its URI.
+    fn fetch_keys(base_url: &str, list: &[Segment], header: &[(String, String)]) -> HashMap<String, Vec<u8>> {
+        let client = reqwest::blocking::Client::new();
+        let mut keys = HashMap::new();
+        for seg in list {
+            if let Some(key_uri) = &seg.key_uri {
+                if keys.contains_key(key_uri) {
+                    continue;
+                }
+                let url = Url::parse(base_url).unwrap().join(key_uri).unwrap();
+                let body = M3U8::apply_headers(client.get(url.as_str()), header);
+                match body.send().and_then(|resp| resp.bytes()) {
+                    Ok(bytes) => { keys.insert(key_uri.clone(), bytes.to_vec()); },
+                    Err(e) => println!("key: {} download failed, error: {}", key_uri, e)
+                }
+            }
+        }
+        keys
+    }
+
+    // Returns `None` on a missing key or a decryption failure rather than
+    // falling back to the ciphertext: writing undecrypted bytes out as if
+    // they were the finished segment would be a silent corruption that
+    // `--resume` would then treat as already complete.
+    fn decrypt_segment(seg: &Segment, keys: &HashMap<String, Vec<u8>>, data: Vec<u8>) -> Option<Vec<u8>> {
+        let key_uri = match &seg.key_uri {
+            Some(k) => k,
+            None => return Some(data)
+        };
+        let key = match keys.get(key_uri) {
+            Some(k) => k,
+            None => {
+                println!("ts: {} decryption failed, key {} unavailable", &seg.uri, key_uri);
+                return None;
             }
+        };
+        let iv = seg.iv.unwrap_or_else(|| M3U8::default_iv(seg.sequence));
+        match decrypt(Cipher::aes_128_cbc(), key, Some(&iv), &data) {
+            Ok(plain) => Some(plain),
+            Err(e) => {
+                println!("ts: {} decryption failed, error: {}", &seg.uri, e);
+                None
+            }
+        }
+    }
+
+    const MAX_TRIES: u32 = 4;
+
+    // Streams a single attempt's response body straight to `tmp_path`,
+    // bounding memory regardless of segment size. Any partial file from a
+    // failed attempt is discarded before the next retry.
+    fn stream_attempt(client: &reqwest::blocking::Client, url: &Url, header: &[(String, String)], tmp_path: &Path) -> Result<(), String> {
+        let body = M3U8::apply_headers(client.get(url.as_str()), header);
+        let resp = body.send().map_err(|e| e.to_string())?;
+        // `send()` succeeds for 4xx/5xx responses too; without this check an
+        // error page gets streamed to disk and treated as a completed segment.
+        let mut resp = resp.error_for_status().map_err(|e| e.to_string())?;
+        let mut tmp_file = fs::File::create(tmp_path).map_err(|e| e.to_string())?;
+        io::copy(&mut resp, &mut tmp_file).map_err(|e| e.to_string())?;
+        Ok(())
+    }
 
-            // deal with the http request error, try our best to download more ts files.
-            match body.send() {
+    // Downloads a segment to `<output>/<filename>.tmp`, retrying transport
+    // errors with exponential backoff (1s, 2s, 4s between the `MAX_TRIES`
+    // attempts), then decrypts it
+    // (if encrypted) and atomically renames it into place so a crash mid-write
+    // never leaves a truncated file masquerading as a completed one.
+    fn fetch_ts_to_file(client: &reqwest::blocking::Client, url: &Url, header: &[(String, String)], limiter: &HostLimiter,
+                         seg: &Segment, keys: &HashMap<String, Vec<u8>>, output: &str) -> bool {
+        let host = url.host_str().unwrap_or("").to_string();
+        let tmp_path = Path::new(output).join(format!("{}.tmp", seg.filename));
+        let final_path = Path::new(output).join(&seg.filename);
+
+        let mut delay = Duration::from_secs(1);
+        let mut succeeded = false;
+        for attempt in 1..=M3U8::MAX_TRIES {
+            limiter.acquire(&host);
+            let result = M3U8::stream_attempt(client, url, header, &tmp_path);
+            limiter.release(&host);
+
+            match result {
+                Ok(()) => { succeeded = true; break; },
                 Err(e) => {
-                    println!("ts: {} download failed, error: {}", &ts, e);
-                    continue;
-                },
-                Ok(resp) => {
-                    if let Ok(body) = resp.bytes() {
-                        let content: Result<Vec<_>, _> = body.bytes().collect();
-                        if let Ok(data) = content {
-                            tx.send((String::from(ts), data)).unwrap();
-                        }
-                    } else {
-                        println!("ts: {} download failed, parse error", &ts);
-                        continue;
-                    }
+                    let _ = fs::remove_file(&tmp_path);
+                    println!("ts: {} download failed (try {}/{}), error: {}", url, attempt, M3U8::MAX_TRIES, e);
+                }
+            }
+
+            if attempt < M3U8::MAX_TRIES {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+
+        if !succeeded {
+            println!("ts: {} gave up after {} tries", url, M3U8::MAX_TRIES);
+            return false;
+        }
+
+        if seg.key_uri.is_some() {
+            let data = fs::read(&tmp_path).unwrap();
+            let data = match M3U8::decrypt_segment(seg, keys, data) {
+                Some(data) => data,
+                None => {
+                    let _ = fs::remove_file(&tmp_path);
+                    println!("ts: {} gave up, undecryptable", &seg.filename);
+                    return false;
                 }
+            };
+            if let Err(e) = fs::write(&tmp_path, data) {
+                let _ = fs::remove_file(&tmp_path);
+                println!("ts: {} decrypted write failed, error: {}", &seg.filename, e);
+                return false;
+            }
+        }
+
+        fs::rename(&tmp_path, &final_path).unwrap();
+        true
+    }
+
+    fn download_ts(base_url: &str, list: &[Segment], tx: &Sender<String>, header: &[(String, String)], keys: &HashMap<String, Vec<u8>>, limiter: &HostLimiter, output: &str)
+    {
+        let client = reqwest::blocking::Client::new();
+
+        for seg in list {
+            let url = Url::parse(base_url).unwrap().join(&seg.uri).unwrap();
+            if M3U8::fetch_ts_to_file(&client, &url, header, limiter, seg, keys, output) {
+                tx.send(seg.filename.clone()).unwrap();
             }
         }
     }
@@ -103,53 +503,62 @@ impl M3U8 {
         return root_path.join(&ts).exists();
     }
 
-    pub fn download(&self, thread_num: i32) {
+    // Returns the full, playlist-ordered segment list (regardless of what
+    // `--resume` skipped downloading) so callers like `merge` can reuse it
+    // instead of re-parsing or re-fetching the playlist.
+    pub fn download(&self, thread_num: i32) -> Vec<Segment> {
         if !Path::new(&self.output).exists() {
             fs::create_dir_all(&self.output).unwrap();
         }
-        let mut list = M3U8::load_m3u8(&self.path);
-        if list.len() == 0 {
+        let full_list = self.load_m3u8();
+        if full_list.len() == 0 {
             println!("m3u8 format is invalid");
             process::exit(0);
         }
 
         // Don't download the downloaded file if the file already existed.
-        if self.resume {
-            list = list.iter()
-                    .filter(|ts| !self.check_exist(ts))
-                    .map(|ts| ts.to_owned())
-                    .collect();
-        }
+        let list: Vec<Segment> = if self.resume {
+            full_list.iter()
+                    .filter(|seg| !self.check_exist(&seg.filename))
+                    .map(|seg| seg.to_owned())
+                    .collect()
+        } else {
+            full_list.clone()
+        };
 
         if list.len() == 0{
            println!("Done!");
-           process::exit(0);
+           return full_list;
         }
 
         let mut thread_pool: Vec<thread::JoinHandle<_>> = vec![];
-        let iter = list.chunks(list.len() / (thread_num as usize));
-        let (tx, rx): (Sender<(String, Vec<u8>)>, Receiver<(String, Vec<u8>)>) = mpsc::channel();
-        let output_ref = self.output.clone();
+        let chunk_size = (list.len() / (thread_num as usize)).max(1);
+        let iter = list.chunks(chunk_size);
+        let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
         let total = list.len();
         thread_pool.push(thread::spawn( move || {
             let mut pb = ProgressBar::new(total as u64);
-            for data in rx {
-                let path = Path::new(&output_ref).join(&data.0);
-                fs::write(path, data.1).unwrap();
+            for _ in rx {
                 pb.inc();
             }
             pb.finish_print("Done!");
         }));
-        
+
         let base_url = Arc::new(self.base_url.clone());
         let header = Arc::new(self.parse_header());
+        let keys = Arc::new(M3U8::fetch_keys(&base_url, &list, &header));
+        let limiter = Arc::new(HostLimiter::new(self.max_per_host));
+        let output = Arc::new(self.output.clone());
         for i in iter {
             let data = i.to_vec();
             let tx = tx.clone();
             let base_url = Arc::clone(&base_url);
             let header = Arc::clone(&header);
+            let keys = Arc::clone(&keys);
+            let limiter = Arc::clone(&limiter);
+            let output = Arc::clone(&output);
             thread_pool.push(thread::spawn( move || {
-                M3U8::download_ts(&base_url, &data, &tx, &header);
+                M3U8::download_ts(&base_url, &data, &tx, &header, &keys, &limiter, &output);
             }));
         }
 
@@ -158,6 +567,37 @@ impl M3U8 {
         for t in thread_pool {
             t.join().unwrap();
         }
+
+        full_list
+    }
+
+    // Concatenates downloaded segments into a single file, in playlist order
+    // (not filesystem order), using a buffered pipeline so the whole stream
+    // is never held in memory at once. Safe to retry: nothing is deleted
+    // unless `cleanup` is set, so a failed merge can simply be re-run.
+    // `list` is the segment list `download` already parsed, so merging
+    // doesn't re-fetch or re-select a variant playlist over the network.
+    pub fn merge(&self, list: &[Segment], output_path: &str, cleanup: bool) {
+        let missing = list.iter().filter(|seg| !self.check_exist(&seg.filename)).count();
+        if missing > 0 {
+            println!("cannot merge: {} segment(s) missing, download them first", missing);
+            process::exit(0);
+        }
+
+        let mut out = io::BufWriter::new(fs::File::create(output_path).unwrap());
+        for seg in list {
+            let path = Path::new(&self.output).join(&seg.filename);
+            let mut segment_file = io::BufReader::new(fs::File::open(&path).unwrap());
+            io::copy(&mut segment_file, &mut out).unwrap();
+        }
+        out.flush().unwrap();
+
+        if cleanup {
+            for seg in list {
+                let _ = fs::remove_file(Path::new(&self.output).join(&seg.filename));
+            }
+        }
+        println!("merged {} segments into {}", list.len(), output_path);
     }
 }
 
@@ -166,21 +606,36 @@ fn main() {
         .version("1.0")
         .author("XBlame <xblame@qq.com>")
         .about("Multi-thread m3u8 downloader")
-        .arg(arg!(-f --file <FILE> "the local path of the m3u8 file").required(true))
+        .arg(arg!(-f --file <FILE> "the local path of the m3u8 file; omit to auto-fetch it from -u").required(false))
         .arg(arg!(-u --url  <URL> "the url of the m3u8 file").required(true))
         .arg(arg!(-d --dest <DIR> "the path of output dir").required(false))
         .arg(arg!(-j --j <N> "multi-thread number, default: 8").required(false))
         .arg(arg!(--header <JSON_FILE> "http request header, you can input a json file to declare it.").required(false))
         .arg(arg!(-r --resume "resume from break-point").required(false).takes_value(false))
+        .arg(arg!(-q --quality <QUALITY> "variant to pick from a master playlist: best|worst|<height>p, default: best").required(false))
+        .arg(arg!(--"max-per-host" <N> "max concurrent requests per host, default: 4").required(false))
+        .arg(arg!(--merge <OUTPUT> "merge downloaded segments into a single file, in playlist order").required(false))
+        .arg(arg!(--cleanup "delete segment fragments after a successful merge").required(false).takes_value(false))
+        .arg(arg!(--"cache-ttl" <SECONDS> "how long an auto-fetched playlist stays cached, default: 259200 (3 days)").required(false))
+        .arg(arg!(--refresh "ignore the cached playlist and re-fetch it from -u").required(false).takes_value(false))
         .get_matches();
 
-    let file_path = matches.value_of("file").unwrap();
+    let file_path = matches.value_of("file").unwrap_or("");
     let url = matches.value_of("url").unwrap();
     let dest = matches.value_of("dest").unwrap_or("./");
     let thread_num: i32 = matches.value_of_t("j").unwrap_or(8);
     let header = matches.value_of("header").unwrap_or("");
     let resume = matches.is_present("resume");
+    let quality = matches.value_of("quality").unwrap_or("best");
+    let max_per_host: usize = matches.value_of_t("max-per-host").unwrap_or(4);
+    let merge = matches.value_of("merge").map(String::from);
+    let cleanup = matches.is_present("cleanup");
+    let cache_ttl = Duration::from_secs(matches.value_of_t("cache-ttl").unwrap_or(3 * 24 * 60 * 60));
+    let refresh = matches.is_present("refresh");
 
-    let config: M3U8 = M3U8::new(file_path,url, dest, header, resume);
-    config.download(thread_num);
+    let config: M3U8 = M3U8::new(file_path,url, dest, header, resume, quality, max_per_host, cache_ttl, refresh);
+    let list = config.download(thread_num);
+    if let Some(merge_path) = &merge {
+        config.merge(&list, merge_path, cleanup);
+    }
 }